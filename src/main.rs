@@ -1,23 +1,43 @@
+use async_trait::async_trait;
+use aws_sdk_s3::types::ByteStream;
 use calamine::{RangeDeserializerBuilder, Reader, Xlsx};
 use csv::Writer;
-use lambda_runtime::error::HandlerError;
+use flate2::read::GzDecoder;
+use futures::stream::{self, StreamExt};
 use openssl::ssl::{SslConnector, SslMethod};
 use percent_encoding::percent_decode_str;
 use postgres::Client;
 use postgres_openssl::MakeTlsConnector;
-use rusoto_core::Region;
-use rusoto_s3::{GetObjectRequest, PutObjectRequest, S3Client, S3};
-use rusoto_secretsmanager::{GetSecretValueRequest, SecretsManager, SecretsManagerClient};
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::HashSet;
-use std::error::Error;
 use std::io::Cursor;
 use std::io::Read;
 
 const INPUT_BUCKET: &str = "input-bucket-name";
 const OUTPUT_BUCKET: &str = "output-bucket-name";
 const COLUMNS: [&str; 4] = ["location", "metric", "value", "date"];
+const ALLOWED_TABLES: [&str; 1] = ["test_table"];
+
+/// Guards against SQL injection via `format!`-ed identifiers (table/column
+/// names can't be bound as query parameters) by checking against a fixed
+/// allowlist instead of escaping.
+fn validate_identifier(candidate: &str, allowed: &[&str]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if allowed.contains(&candidate) {
+        Ok(())
+    } else {
+        Err(format!("identifier not allowed: {}", candidate).into())
+    }
+}
+
+/// Escapes a value for interpolation into a single-quoted SQL string literal
+/// (the `COPY ... FROM '<s3 path>'` clause can't bind `bucket`/`key` as query
+/// parameters). `bucket`/`key` ultimately derive from an uploaded object's own
+/// key, so this closes the same untrusted-content hole as
+/// `validate_identifier` does for table/column names.
+fn escape_sql_string_literal(value: &str) -> String {
+    value.replace('\'', "''")
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 struct RawExcelRow {
@@ -64,46 +84,413 @@ struct DBCredentials {
     dbClusterIdentifier: String,
 }
 
-fn get_excel_from_s3(
+/// Output-side behavior flags for the upload/COPY step. Defaults preserve the
+/// historical plaintext-CSV behavior.
+#[derive(Debug, Clone, Copy, Default)]
+struct OutputConfig {
+    /// Encode the output CSV with zstd and append `ZSTD` to the COPY options.
+    compress: bool,
+}
+
+/// Reads `OUTPUT_COMPRESS` so deployments that want zstd-compressed output
+/// can opt in; unset (or anything other than `true`) keeps the default
+/// plaintext-CSV behavior.
+fn output_config_from_env() -> OutputConfig {
+    let compress = std::env::var("OUTPUT_COMPRESS")
+        .map(|value| value == "true")
+        .unwrap_or(false);
+    OutputConfig { compress }
+}
+
+/// Abstracts the object storage and secrets access the pipeline needs, so the
+/// pipeline itself can be exercised against an in-memory backend in tests.
+#[async_trait]
+trait Storage: Send + Sync {
+    async fn get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>;
+    async fn put_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        bytes: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+    async fn get_secret(&self, id: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>>;
+    async fn list_objects(
+        &self,
+        bucket: &str,
+        prefix: &str,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// Abstracts the warehouse-side truncate/COPY steps performed after a CSV has
+/// been uploaded, so they can be exercised without a real Redshift/Postgres.
+trait Warehouse: Send {
+    fn truncate(
+        &mut self,
+        table: &str,
+        canonical_date: &chrono::NaiveDate,
+        locations: &HashSet<String>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+    fn copy_from_s3(
+        &mut self,
+        table: &str,
+        columns: &[&str],
+        bucket: &str,
+        key: &str,
+        config: &OutputConfig,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// Wraps the AWS SDK clients injected at startup, rather than each pipeline
+/// step constructing its own client per call.
+struct S3Storage {
+    s3_client: aws_sdk_s3::Client,
+    sm_client: aws_sdk_secretsmanager::Client,
+}
+
+impl S3Storage {
+    fn new(s3_client: aws_sdk_s3::Client, sm_client: aws_sdk_secretsmanager::Client) -> Self {
+        S3Storage { s3_client, sm_client }
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        println!("Reading bucket: {}, key: {}", bucket, key);
+        let response = self
+            .s3_client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await?;
+
+        let bytes = response.body.collect().await?.into_bytes();
+        Ok(bytes.to_vec())
+    }
+
+    async fn put_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        bytes: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.s3_client
+            .put_object()
+            .bucket(bucket)
+            .key(key)
+            .body(ByteStream::from(bytes))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn get_secret(&self, id: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let secret = self.sm_client.get_secret_value().secret_id(id).send().await?;
+
+        secret
+            .secret_string
+            .ok_or_else(|| format!("secret {} has no string value", id).into())
+    }
+
+    async fn list_objects(
+        &self,
+        bucket: &str,
+        prefix: &str,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut keys = Vec::new();
+        let mut continuation_token: Option<String> = None;
+
+        loop {
+            let mut request = self
+                .s3_client
+                .list_objects_v2()
+                .bucket(bucket)
+                .prefix(prefix);
+            if let Some(token) = &continuation_token {
+                request = request.continuation_token(token);
+            }
+            let response = request.send().await?;
+
+            keys.extend(
+                response
+                    .contents
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|object| object.key),
+            );
+
+            continuation_token = response.next_continuation_token;
+            if continuation_token.is_none() {
+                break;
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+struct PostgresWarehouse {
+    client: Client,
+}
+
+impl PostgresWarehouse {
+    fn connect(credentials: &DBCredentials) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        builder.set_ca_file("redshift-ssl-ca-cert.pem")?;
+        let connector = MakeTlsConnector::new(builder.build());
+
+        let client = Client::connect(
+            format!(
+                "host={} port={} dbname={} user={} password={} sslmode=require",
+                credentials.host, credentials.port, "dbname", credentials.username, credentials.password
+            )
+            .as_str(),
+            connector,
+        )?;
+
+        Ok(PostgresWarehouse { client })
+    }
+}
+
+impl Warehouse for PostgresWarehouse {
+    fn truncate(
+        &mut self,
+        table: &str,
+        canonical_date: &chrono::NaiveDate,
+        locations: &HashSet<String>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        validate_identifier(table, &ALLOWED_TABLES)?;
+
+        let locations_vec: Vec<&str> = locations.iter().map(String::as_str).collect();
+        let truncate_query = format!(
+            "DELETE FROM public.{} WHERE date = $1 AND location = ANY($2);",
+            table
+        );
+        println!("{}", truncate_query);
+        self.client
+            .execute(truncate_query.as_str(), &[canonical_date, &locations_vec])?;
+        Ok(())
+    }
+
+    fn copy_from_s3(
+        &mut self,
+        table: &str,
+        columns: &[&str],
+        bucket: &str,
+        key: &str,
+        config: &OutputConfig,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        validate_identifier(table, &ALLOWED_TABLES)?;
+        for column in columns {
+            validate_identifier(column, &COLUMNS)?;
+        }
+
+        let colstr = columns.join(",");
+        let compression_option = if config.compress { "ZSTD" } else { "" };
+        let escaped_bucket = escape_sql_string_literal(bucket);
+        let escaped_key = escape_sql_string_literal(key);
+        let copy_query = format!(
+            "COPY public.{} ({}) from
+                 's3://{}/{}'
+                  iam_role 'arn:aws:iam::YOUR_ROLE_HERE'
+                  FORMAT CSV
+                  {}
+                  EMPTYASNULL
+                  BLANKSASNULL
+                  IGNOREHEADER 1
+                  IGNOREBLANKLINES
+                  ;",
+            table, colstr, escaped_bucket, escaped_key, compression_option
+        );
+        println!("{}", copy_query);
+        self.client.execute(copy_query.as_str(), &[])?;
+        Ok(())
+    }
+}
+
+/// In-memory `Storage` backend keyed by `(bucket, key)`, for tests. `Clone`
+/// gives each `process_keys` task an independent snapshot of whatever was
+/// seeded in, the same role `S3Storage`'s cheaply-cloned SDK clients play.
+#[cfg(test)]
+use std::collections::HashMap;
+
+#[cfg(test)]
+#[derive(Default, Clone)]
+struct InMemoryStorage {
+    objects: HashMap<(String, String), Vec<u8>>,
+    secrets: HashMap<String, String>,
+}
+
+#[cfg(test)]
+impl InMemoryStorage {
+    fn new() -> Self {
+        InMemoryStorage::default()
+    }
+
+    fn with_object(mut self, bucket: &str, key: &str, bytes: Vec<u8>) -> Self {
+        self.objects.insert((bucket.to_string(), key.to_string()), bytes);
+        self
+    }
+
+    fn with_secret(mut self, id: &str, value: &str) -> Self {
+        self.secrets.insert(id.to_string(), value.to_string());
+        self
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Storage for InMemoryStorage {
+    async fn get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        self.objects
+            .get(&(bucket.to_string(), key.to_string()))
+            .cloned()
+            .ok_or_else(|| format!("no such object: {}/{}", bucket, key).into())
+    }
+
+    async fn put_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        bytes: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.objects.insert((bucket.to_string(), key.to_string()), bytes);
+        Ok(())
+    }
+
+    async fn get_secret(&self, id: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        self.secrets
+            .get(id)
+            .cloned()
+            .ok_or_else(|| format!("no such secret: {}", id).into())
+    }
+
+    async fn list_objects(
+        &self,
+        bucket: &str,
+        prefix: &str,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut keys: Vec<String> = self
+            .objects
+            .keys()
+            .filter(|(object_bucket, key)| object_bucket == bucket && key.starts_with(prefix))
+            .map(|(_, key)| key.clone())
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+/// In-memory `Warehouse` backend that records the queries it would have run,
+/// for tests.
+#[cfg(test)]
+#[derive(Default)]
+struct InMemoryWarehouse {
+    truncated: Vec<(String, chrono::NaiveDate, HashSet<String>)>,
+    copied: Vec<(String, Vec<String>, String, String, bool)>,
+}
+
+#[cfg(test)]
+impl InMemoryWarehouse {
+    fn new() -> Self {
+        InMemoryWarehouse::default()
+    }
+}
+
+#[cfg(test)]
+impl Warehouse for InMemoryWarehouse {
+    fn truncate(
+        &mut self,
+        table: &str,
+        canonical_date: &chrono::NaiveDate,
+        locations: &HashSet<String>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.truncated
+            .push((table.to_string(), *canonical_date, locations.clone()));
+        Ok(())
+    }
+
+    fn copy_from_s3(
+        &mut self,
+        table: &str,
+        columns: &[&str],
+        bucket: &str,
+        key: &str,
+        config: &OutputConfig,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.copied.push((
+            table.to_string(),
+            columns.iter().map(|s| s.to_string()).collect(),
+            bucket.to_string(),
+            key.to_string(),
+            config.compress,
+        ));
+        Ok(())
+    }
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Fetches an object's bytes, transparently inflating it if it is gzipped —
+/// either because `key` ends in `.gz`, or because the buffer starts with the
+/// gzip magic bytes.
+async fn get_object_bytes(
+    storage: &dyn Storage,
     bucket: &str,
     key: &str,
-) -> Result<Xlsx<Cursor<Vec<u8>>>, Box<dyn std::error::Error>> {
-    let mut buffer: Vec<u8> = Vec::new();
-    let s3_client = S3Client::new(Region::EuWest1);
-
-    println!("Reading bucket: {}, key: {}", bucket, key);
-    let s3file = s3_client
-        .get_object(GetObjectRequest {
-            bucket: bucket.to_string(),
-            key: key.to_string(),
-            ..Default::default()
-        })
-        .sync()?;
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let buffer = storage.get_object(bucket, key).await?;
 
-    let _file = s3file
-        .body
-        .unwrap()
-        .into_blocking_read()
-        .read_to_end(&mut buffer)?;
-    Ok(Xlsx::new(Cursor::new(buffer))?)
-}
+    let is_gzip = key.ends_with(".gz") || buffer.starts_with(&GZIP_MAGIC);
+    if !is_gzip {
+        return Ok(buffer);
+    }
 
-fn excel_to_csv_string(
-    mut excel: Xlsx<Cursor<Vec<u8>>>,
-) -> Result<(String, HashSet<String>, chrono::NaiveDate), Box<dyn std::error::Error>> {
-    let range = excel
-        .worksheet_range("data")
-        .ok_or(calamine::Error::Msg("Cannot find data worksheet"))??;
+    let mut decoder = GzDecoder::new(Cursor::new(buffer));
+    let mut inflated = Vec::new();
+    decoder.read_to_end(&mut inflated)?;
+    Ok(inflated)
+}
 
-    let mut iter_result =
-        RangeDeserializerBuilder::with_headers(&COLUMNS).from_range::<_, RawExcelRow>(&range)?;
+async fn get_excel_from_s3(
+    storage: &dyn Storage,
+    bucket: &str,
+    key: &str,
+) -> Result<Xlsx<Cursor<Vec<u8>>>, Box<dyn std::error::Error + Send + Sync>> {
+    let buffer = get_object_bytes(storage, bucket, key).await?;
+    Ok(Xlsx::new(Cursor::new(buffer))?)
+}
 
+/// Drains a `RawExcelRow` iterator (however it was produced) into a CSV
+/// string, keeping only rows matching the canonical date taken from the
+/// first row. Shared by the XLSX and CSV ingestion paths.
+fn filter_rows<I>(
+    mut iter_result: I,
+) -> Result<(String, HashSet<String>, chrono::NaiveDate), Box<dyn std::error::Error + Send + Sync>>
+where
+    I: Iterator<Item = Result<RawExcelRow, Box<dyn std::error::Error + Send + Sync>>>,
+{
     // Use date of first row as date for file
     let mut wtr = Writer::from_writer(vec![]);
     let mut locations: HashSet<String> = HashSet::new();
 
-    let first_row = iter_result.next().unwrap()?;
-    let canonical_date = first_row.date.clone();
+    let first_row = iter_result
+        .next()
+        .ok_or("no rows to process: input is empty")??;
+    let canonical_date = first_row.date;
     locations.insert(first_row.location.clone());
     wtr.serialize(first_row)?;
     println!("Canonical date: {:?}", canonical_date);
@@ -125,151 +512,393 @@ fn excel_to_csv_string(
     Ok((data, locations, canonical_date))
 }
 
-fn upload_csv_to_s3(
+fn excel_to_csv_string(
+    mut excel: Xlsx<Cursor<Vec<u8>>>,
+) -> Result<(String, HashSet<String>, chrono::NaiveDate), Box<dyn std::error::Error + Send + Sync>> {
+    let range = excel
+        .worksheet_range("data")
+        .ok_or(calamine::Error::Msg("Cannot find data worksheet"))??;
+
+    let iter_result = RangeDeserializerBuilder::with_headers(&COLUMNS)
+        .from_range::<_, RawExcelRow>(&range)?
+        .map(|row| row.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>));
+
+    filter_rows(iter_result)
+}
+
+/// Mirrors `RawExcelRow`, but deserializes from the plain-text values a CSV
+/// reader produces rather than calamine's typed `DataType`.
+#[derive(Deserialize)]
+struct RawCsvRow {
+    location: String,
+    metric: String,
+    #[serde(deserialize_with = "de_csv_opt_f64")]
+    value: Option<f64>,
+    #[serde(deserialize_with = "de_csv_date")]
+    date: chrono::NaiveDate,
+}
+
+fn de_csv_opt_f64<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let raw = String::deserialize(deserializer)?;
+    Ok(raw.trim().parse::<f64>().ok())
+}
+
+fn de_csv_date<'de, D>(deserializer: D) -> Result<chrono::NaiveDate, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let raw = String::deserialize(deserializer)?;
+    chrono::NaiveDate::parse_from_str(raw.trim(), "%Y-%m-%d").map_err(serde::de::Error::custom)
+}
+
+impl From<RawCsvRow> for RawExcelRow {
+    fn from(row: RawCsvRow) -> Self {
+        RawExcelRow {
+            location: row.location,
+            metric: row.metric,
+            value: row.value,
+            date: row.date,
+        }
+    }
+}
+
+fn csv_to_csv_string(
+    bytes: Vec<u8>,
+) -> Result<(String, HashSet<String>, chrono::NaiveDate), Box<dyn std::error::Error + Send + Sync>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .from_reader(Cursor::new(bytes));
+
+    let iter_result = reader.deserialize::<RawCsvRow>().map(|row| {
+        row.map(RawExcelRow::from)
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+    });
+
+    filter_rows(iter_result)
+}
+
+async fn upload_csv_to_s3(
+    storage: &mut dyn Storage,
     data: String,
     label: &str,
     canonical_date: &chrono::NaiveDate,
-) -> Result<String, Box<dyn std::error::Error>> {
+    config: &OutputConfig,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let (bytes, extension) = if config.compress {
+        (zstd::encode_all(data.as_bytes(), 0)?, "csv.zst")
+    } else {
+        (data.into_bytes(), "csv")
+    };
+
     let outputkey = format!(
-        "output/{}_{}.csv",
+        "output/{}_{}.{}",
         label,
-        canonical_date.format("%Y-%m-%d").to_string()
+        canonical_date.format("%Y-%m-%d"),
+        extension
     );
 
-    // Write CSV to S3
-    let s3_client = S3Client::new(Region::EuWest1);
-    s3_client
-        .put_object(PutObjectRequest {
-            bucket: String::from(OUTPUT_BUCKET),
-            key: outputkey.clone(),
-            body: Some(data.into_bytes().into()),
-            ..Default::default()
-        })
-        .sync()?;
+    storage.put_object(OUTPUT_BUCKET, &outputkey, bytes).await?;
 
     Ok(outputkey)
 }
 
-fn get_db_credentials() -> Result<DBCredentials, Box<dyn std::error::Error>> {
-    let sm_client = SecretsManagerClient::new(Region::EuWest1);
-    let secret = sm_client
-        .get_secret_value(GetSecretValueRequest {
-            secret_id: "db_credentials_secret".to_string(),
-            version_id: None,
-            version_stage: None,
-        })
-        .sync()?;
-
-    let credentials: DBCredentials = serde_json::from_str(&secret.secret_string.unwrap())?;
+async fn get_db_credentials(
+    storage: &dyn Storage,
+) -> Result<DBCredentials, Box<dyn std::error::Error + Send + Sync>> {
+    let secret = storage.get_secret("db_credentials_secret").await?;
+    let credentials: DBCredentials = serde_json::from_str(&secret)?;
 
     Ok(credentials)
 }
 
 fn load_to_db(
+    warehouse: &mut dyn Warehouse,
     outputkey: &str,
     canonical_date: &chrono::NaiveDate,
     locations: &HashSet<String>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut builder = SslConnector::builder(SslMethod::tls())?;
-    builder.set_ca_file("redshift-ssl-ca-cert.pem")?;
-    let connector = MakeTlsConnector::new(builder.build());
-
-    let credentials = get_db_credentials()?;
-    let mut client = Client::connect(
-        format!(
-            "host={} port={} dbname={} user={} password={} sslmode=require",
-            credentials.host,
-            credentials.port,
-            "dbname",
-            credentials.username,
-            credentials.password
-        )
-        .as_str(),
-        connector,
-    )?;
-
-    let locations_vec: Vec<String> = locations
-        .iter()
-        .cloned()
-        .map(|x| format!("'{}'", x))
-        .collect();
-
+    config: &OutputConfig,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let target_table = "test_table";
 
-    let location_string = &locations_vec.join(",");
-    let truncate_query = format!(
-        "DELETE FROM public.{} WHERE date = '{}' AND location IN ({});",
-        target_table,
-        canonical_date.format("%Y-%m-%d").to_string(),
-        location_string
-    );
-    let colstr = &COLUMNS.join(",");
-    println!("{}", truncate_query);
-    let copy_query = format!(
-        "COPY public.{} ({}) from
-                 's3://{}/{}'
-                  iam_role 'arn:aws:iam::YOUR_ROLE_HERE'
-                  FORMAT CSV
-                  EMPTYASNULL
-                  BLANKSASNULL
-                  IGNOREHEADER 1
-                  IGNOREBLANKLINES
-                  ;",
-        target_table, colstr, OUTPUT_BUCKET, outputkey
-    );
-    println!("{}", copy_query);
-    println!("{:?}", client.execute(truncate_query.as_str(), &[]));
-    println!("{:?}", client.execute(copy_query.as_str(), &[]));
+    warehouse.truncate(target_table, canonical_date, locations)?;
+    warehouse.copy_from_s3(target_table, &COLUMNS, OUTPUT_BUCKET, outputkey, config)?;
 
     Ok(())
 }
 
-fn handle_excel(key: &str) -> Result<(), Box<dyn std::error::Error>> {
+/// Result of the storage-only half of ingesting one object: the uploaded
+/// output CSV's key plus the metadata `load_to_db` needs, with no warehouse
+/// connection involved yet.
+struct PreparedUpload {
+    outputkey: String,
+    canonical_date: chrono::NaiveDate,
+    locations: HashSet<String>,
+}
+
+async fn prepare_excel(
+    storage: &mut dyn Storage,
+    key: &str,
+    config: &OutputConfig,
+) -> Result<PreparedUpload, Box<dyn std::error::Error + Send + Sync>> {
     let label = key.split("/").nth(1).unwrap();
-    let excel: Xlsx<_> = get_excel_from_s3(INPUT_BUCKET, &key)?;
+    let excel: Xlsx<_> = get_excel_from_s3(storage, INPUT_BUCKET, key).await?;
 
     let (data, locations, canonical_date) = excel_to_csv_string(excel)?;
 
-    let outputkey = upload_csv_to_s3(data, label, &canonical_date)?;
+    let outputkey = upload_csv_to_s3(storage, data, label, &canonical_date, config).await?;
 
-    load_to_db(&outputkey, &canonical_date, &locations)?;
-    Ok(())
+    Ok(PreparedUpload {
+        outputkey,
+        canonical_date,
+        locations,
+    })
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    lambda_runtime::lambda!(my_handler);
+async fn prepare_csv(
+    storage: &mut dyn Storage,
+    key: &str,
+    config: &OutputConfig,
+) -> Result<PreparedUpload, Box<dyn std::error::Error + Send + Sync>> {
+    let label = key.split("/").nth(1).unwrap();
+    let bytes = get_object_bytes(storage, INPUT_BUCKET, key).await?;
 
-    Ok(())
+    let (data, locations, canonical_date) = csv_to_csv_string(bytes)?;
+
+    let outputkey = upload_csv_to_s3(storage, data, label, &canonical_date, config).await?;
+
+    Ok(PreparedUpload {
+        outputkey,
+        canonical_date,
+        locations,
+    })
 }
 
-fn my_handler(
-    e: aws_lambda_events::event::s3::S3Event,
-    _c: lambda_runtime::Context,
-) -> Result<(), HandlerError> {
-    println!("{:?}", e);
-    let decodedkey = percent_decode_str(&(e.records[0].s3.object.key.as_ref()).unwrap())
-        .decode_utf8()
-        .unwrap();
+/// Dispatches on the object key's extension (ignoring a trailing `.gz`)
+/// between the XLSX and CSV ingestion paths. Touches only `storage`, so
+/// callers processing many keys concurrently can run this part of the
+/// pipeline in parallel and reserve the warehouse lock for `load_to_db`.
+async fn prepare_object(
+    storage: &mut dyn Storage,
+    key: &str,
+    config: &OutputConfig,
+) -> Result<PreparedUpload, Box<dyn std::error::Error + Send + Sync>> {
+    let stripped = key.strip_suffix(".gz").unwrap_or(key);
+    if stripped.ends_with(".csv") {
+        prepare_csv(storage, key, config).await
+    } else {
+        prepare_excel(storage, key, config).await
+    }
+}
+
+async fn handle_object(
+    storage: &mut dyn Storage,
+    warehouse: &mut dyn Warehouse,
+    key: &str,
+    config: &OutputConfig,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let prepared = prepare_object(storage, key, config).await?;
+    load_to_db(
+        warehouse,
+        &prepared.outputkey,
+        &prepared.canonical_date,
+        &prepared.locations,
+        config,
+    )
+}
 
-    match handle_excel(&decodedkey) {
-        Ok(_) => (),
+/// Per-key outcome of a `handle_prefix` batch run.
+#[derive(Debug, Default)]
+struct PrefixSummary {
+    succeeded: Vec<String>,
+    failed: Vec<(String, String)>,
+}
+
+/// Processes a known list of keys concurrently (up to `concurrency` in
+/// flight), given a way to produce a fresh `Storage` per key and a single
+/// `Warehouse` shared (and mutex-guarded) across all of them. `Warehouse` is
+/// shared rather than reconnected per key because it wraps one DB connection
+/// that can't be used from multiple tasks at once and, unlike `Storage`'s
+/// cheap cloned SDK clients, is too expensive to reopen per key. Split out of
+/// `handle_prefix` so it can be exercised directly against
+/// `InMemoryStorage`/`InMemoryWarehouse` in tests.
+async fn process_keys<F, S, W>(
+    keys: Vec<String>,
+    new_storage: F,
+    warehouse: std::sync::Arc<tokio::sync::Mutex<W>>,
+    concurrency: usize,
+    config: OutputConfig,
+) -> PrefixSummary
+where
+    F: Fn() -> S + Send + Sync + 'static,
+    S: Storage + Send + 'static,
+    W: Warehouse + Send + 'static,
+{
+    let new_storage = std::sync::Arc::new(new_storage);
+    let outcomes: Vec<(String, Result<(), String>)> = stream::iter(keys)
+        .map(|key| {
+            let new_storage = new_storage.clone();
+            let warehouse = warehouse.clone();
+            async move {
+                let task_key = key.clone();
+                let outcome = tokio::spawn(async move {
+                    let mut storage = new_storage();
+                    // Fetch/parse/upload happen per-task, against each task's own
+                    // `Storage`, with no lock held — this is what lets different
+                    // keys' pipelines actually overlap. Only the following
+                    // truncate+copy against the single shared DB connection needs
+                    // exclusive access.
+                    let prepared = prepare_object(&mut storage, &task_key, &config)
+                        .await
+                        .map_err(|error| error.to_string())?;
+                    let mut warehouse = warehouse.lock().await;
+                    load_to_db(
+                        &mut *warehouse,
+                        &prepared.outputkey,
+                        &prepared.canonical_date,
+                        &prepared.locations,
+                        &config,
+                    )
+                    .map_err(|error| error.to_string())
+                })
+                .await
+                .unwrap_or_else(|join_error| Err(join_error.to_string()));
+                (key, outcome)
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    let mut summary = PrefixSummary::default();
+    for (key, outcome) in outcomes {
+        match outcome {
+            Ok(()) => summary.succeeded.push(key),
+            Err(error) => summary.failed.push((key, error)),
+        }
+    }
+    summary
+}
+
+/// Lists every object under `prefix` in `INPUT_BUCKET` and processes them
+/// with up to `concurrency` objects in flight at once, collecting per-object
+/// successes and failures instead of aborting on the first error. Intended
+/// for backfills/bulk loads, run via `BATCH_PREFIX` instead of the normal
+/// per-event Lambda path (see `main`). Connects to the warehouse once, up
+/// front, and shares that connection across every key.
+async fn handle_prefix(
+    s3_client: aws_sdk_s3::Client,
+    sm_client: aws_sdk_secretsmanager::Client,
+    prefix: &str,
+    concurrency: usize,
+    config: OutputConfig,
+) -> PrefixSummary {
+    let lister = S3Storage::new(s3_client.clone(), sm_client.clone());
+    let keys = match lister.list_objects(INPUT_BUCKET, prefix).await {
+        Ok(keys) => keys,
         Err(error) => {
-            panic!("Error: {:?}", error);
+            return PrefixSummary {
+                succeeded: Vec::new(),
+                failed: vec![(prefix.to_string(), error.to_string())],
+            }
         }
+    };
+
+    let credentials = match get_db_credentials(&lister).await {
+        Ok(credentials) => credentials,
+        Err(error) => {
+            return PrefixSummary {
+                succeeded: Vec::new(),
+                failed: vec![(prefix.to_string(), error.to_string())],
+            }
+        }
+    };
+    let warehouse = match PostgresWarehouse::connect(&credentials) {
+        Ok(warehouse) => warehouse,
+        Err(error) => {
+            return PrefixSummary {
+                succeeded: Vec::new(),
+                failed: vec![(prefix.to_string(), error.to_string())],
+            }
+        }
+    };
+    let warehouse = std::sync::Arc::new(tokio::sync::Mutex::new(warehouse));
+
+    process_keys(
+        keys,
+        move || S3Storage::new(s3_client.clone(), sm_client.clone()),
+        warehouse,
+        concurrency,
+        config,
+    )
+    .await
+}
+
+#[tokio::main]
+async fn main() -> Result<(), lambda_runtime::Error> {
+    let config = aws_config::load_from_env().await;
+    let s3_client = aws_sdk_s3::Client::new(&config);
+    let sm_client = aws_sdk_secretsmanager::Client::new(&config);
+
+    // Bulk-backfill entry point: set BATCH_PREFIX to process every object
+    // under that S3 prefix once and exit, instead of starting the normal
+    // per-event Lambda runtime loop.
+    if let Ok(prefix) = std::env::var("BATCH_PREFIX") {
+        let concurrency = std::env::var("BATCH_CONCURRENCY")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(4);
+        let summary =
+            handle_prefix(s3_client, sm_client, &prefix, concurrency, output_config_from_env()).await;
+        println!("Batch summary: {:?}", summary);
+        return if summary.failed.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("{} of {} keys failed", summary.failed.len(), summary.succeeded.len() + summary.failed.len()).into())
+        };
     }
 
+    lambda_runtime::run(lambda_runtime::service_fn(
+        move |event: lambda_runtime::LambdaEvent<aws_lambda_events::event::s3::S3Event>| {
+            let s3_client = s3_client.clone();
+            let sm_client = sm_client.clone();
+            async move { my_handler(event, s3_client, sm_client, output_config_from_env()).await }
+        },
+    ))
+    .await
+}
+
+async fn my_handler(
+    event: lambda_runtime::LambdaEvent<aws_lambda_events::event::s3::S3Event>,
+    s3_client: aws_sdk_s3::Client,
+    sm_client: aws_sdk_secretsmanager::Client,
+    config: OutputConfig,
+) -> Result<(), lambda_runtime::Error> {
+    let e = event.payload;
+    println!("{:?}", e);
+    let decodedkey = percent_decode_str((e.records[0].s3.object.key.as_ref()).unwrap()).decode_utf8()?;
+
+    let mut storage = S3Storage::new(s3_client, sm_client);
+    let credentials = get_db_credentials(&storage).await?;
+    let mut warehouse = PostgresWarehouse::connect(&credentials)?;
+
+    handle_object(&mut storage, &mut warehouse, &decodedkey, &config).await?;
+
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::error::Error;
     use std::fs::File;
     use std::io::Write;
     use std::path::PathBuf;
 
     #[test]
-    fn test_local() -> Result<(), Box<dyn Error>> {
+    fn test_local() -> Result<(), Box<dyn Error + Send + Sync>> {
         let mut buffer: Vec<u8> = Vec::new();
         let mut f = File::open(
             PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(PathBuf::from("tests/test_excel.xlsx")),
@@ -287,7 +916,7 @@ mod tests {
 
         assert_eq!(
             canonical_date,
-            chrono::naive::NaiveDate::from_ymd(2020, 2, 1)
+            chrono::naive::NaiveDate::from_ymd_opt(2020, 2, 1).unwrap()
         );
 
         {
@@ -306,4 +935,173 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_pipeline_end_to_end_in_memory() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut f = File::open(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(PathBuf::from("tests/test_excel.xlsx")),
+        )?;
+        f.read_to_end(&mut buffer)?;
+
+        let mut storage = InMemoryStorage::new()
+            .with_object(INPUT_BUCKET, "uploads/acme/test_excel.xlsx", buffer)
+            .with_secret(
+                "db_credentials_secret",
+                r#"{"username":"u","password":"p","engine":"postgres","host":"localhost","port":5432,"dbClusterIdentifier":"test"}"#,
+            );
+        let mut warehouse = InMemoryWarehouse::new();
+
+        handle_object(
+            &mut storage,
+            &mut warehouse,
+            "uploads/acme/test_excel.xlsx",
+            &OutputConfig::default(),
+        )
+        .await?;
+
+        assert_eq!(warehouse.truncated.len(), 1);
+        assert_eq!(warehouse.copied.len(), 1);
+        assert!(storage
+            .objects
+            .keys()
+            .any(|(bucket, key)| bucket == OUTPUT_BUCKET && key.starts_with("output/acme_")));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_object_bytes_inflates_gzip() -> Result<(), Box<dyn Error + Send + Sync>> {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let plain = b"location,metric,value,date\n".to_vec();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&plain)?;
+        let gzipped = encoder.finish()?;
+
+        let storage = InMemoryStorage::new()
+            .with_object(INPUT_BUCKET, "uploads/acme/data.csv.gz", gzipped.clone())
+            .with_object(INPUT_BUCKET, "uploads/acme/data.bin", gzipped);
+
+        // Detected via the `.gz` suffix.
+        let via_suffix = get_object_bytes(&storage, INPUT_BUCKET, "uploads/acme/data.csv.gz").await?;
+        assert_eq!(via_suffix, plain);
+
+        // Detected via the magic bytes even without a `.gz` suffix.
+        let via_magic = get_object_bytes(&storage, INPUT_BUCKET, "uploads/acme/data.bin").await?;
+        assert_eq!(via_magic, plain);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_csv_to_csv_string_filters_to_canonical_date() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let csv = "location,metric,value,date\n\
+                    UK,sales,1.5,2020-02-01\n\
+                    FR,sales,2.5,2020-02-01\n\
+                    UK,sales,9.0,2020-02-02\n";
+
+        let (data, locations, canonical_date) = csv_to_csv_string(csv.as_bytes().to_vec())?;
+
+        let mut test_set = HashSet::with_capacity(2);
+        test_set.insert(String::from("UK"));
+        test_set.insert(String::from("FR"));
+
+        assert_eq!(locations, test_set);
+        assert_eq!(
+            canonical_date,
+            chrono::naive::NaiveDate::from_ymd_opt(2020, 2, 1).unwrap()
+        );
+        // header row plus the two rows matching the canonical date
+        assert_eq!(data.lines().count(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_objects_filters_by_bucket_and_prefix() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let storage = InMemoryStorage::new()
+            .with_object(INPUT_BUCKET, "uploads/acme/a.xlsx", vec![])
+            .with_object(INPUT_BUCKET, "uploads/acme/b.csv", vec![])
+            .with_object(INPUT_BUCKET, "uploads/other/c.xlsx", vec![])
+            .with_object(OUTPUT_BUCKET, "uploads/acme/a.csv", vec![]);
+
+        let keys = storage.list_objects(INPUT_BUCKET, "uploads/acme/").await?;
+
+        assert_eq!(
+            keys,
+            vec![
+                "uploads/acme/a.xlsx".to_string(),
+                "uploads/acme/b.csv".to_string(),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_csv_to_s3_compresses_when_configured() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut storage = InMemoryStorage::new();
+        let canonical_date = chrono::naive::NaiveDate::from_ymd_opt(2020, 2, 1).unwrap();
+
+        let outputkey = upload_csv_to_s3(
+            &mut storage,
+            "location,metric,value,date\n".to_string(),
+            "acme",
+            &canonical_date,
+            &OutputConfig { compress: true },
+        )
+        .await?;
+
+        assert_eq!(outputkey, "output/acme_2020-02-01.csv.zst");
+        let stored = storage.get_object(OUTPUT_BUCKET, &outputkey).await?;
+        let decoded = zstd::decode_all(Cursor::new(stored))?;
+        assert_eq!(decoded, b"location,metric,value,date\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_keys_handles_prefix_with_bounded_concurrency() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let csv_for = |location: &str| {
+            format!(
+                "location,metric,value,date\n{},sales,1.0,2020-02-01\n",
+                location
+            )
+            .into_bytes()
+        };
+        let seed = InMemoryStorage::new()
+            .with_object(INPUT_BUCKET, "uploads/acme/a.csv", csv_for("UK"))
+            .with_object(INPUT_BUCKET, "uploads/acme/b.csv", csv_for("FR"));
+        let keys = vec![
+            "uploads/acme/a.csv".to_string(),
+            "uploads/acme/b.csv".to_string(),
+        ];
+        let warehouse = std::sync::Arc::new(tokio::sync::Mutex::new(InMemoryWarehouse::new()));
+
+        let summary = process_keys(
+            keys,
+            move || seed.clone(),
+            warehouse.clone(),
+            2,
+            OutputConfig::default(),
+        )
+        .await;
+
+        assert_eq!(summary.succeeded.len(), 2);
+        assert!(summary.failed.is_empty());
+        assert_eq!(warehouse.lock().await.truncated.len(), 2);
+        assert_eq!(warehouse.lock().await.copied.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_identifier_rejects_unknown_names() {
+        assert!(validate_identifier("test_table", &ALLOWED_TABLES).is_ok());
+        assert!(validate_identifier("location", &COLUMNS).is_ok());
+        assert!(validate_identifier("test_table; DROP TABLE test_table;--", &ALLOWED_TABLES).is_err());
+    }
 }